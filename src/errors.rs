@@ -0,0 +1,117 @@
+use failure::Fail;
+use maud::html;
+use rocket::http::{ContentType, Status};
+use rocket::request::Request;
+use rocket::response::{self, Responder, Response};
+use std::io::Cursor;
+
+/// Errors that can occur while validating an Auth0-issued JWT or the keys
+/// used to verify it, or while renewing a session with its refresh token.
+#[derive(Debug, Fail)]
+pub enum AuthError {
+    #[fail(display = "malformed JWT: {}", repr)]
+    MalformedJWT { repr: String },
+    #[fail(display = "JWT has expired")]
+    Expired,
+    #[fail(display = "JWT audience does not match expected audience")]
+    AudienceMismatch,
+    #[fail(display = "JWT issuer does not match expected issuer")]
+    IssuerMismatch,
+    #[fail(display = "malformed JWKS entry")]
+    MalformedJWKS,
+    #[fail(display = "no signing key found for kid: {}", kid)]
+    UnknownSigningKey { kid: String },
+    #[fail(display = "session has no refresh token to renew it with")]
+    NoRefreshToken,
+}
+
+/// Raised when a value fails to serialize for storage in the database.
+#[derive(Debug, Fail)]
+#[fail(display = "could not serialize {}", name)]
+pub struct SerializationError {
+    pub name: String,
+}
+
+/// Raised when a value fails to deserialize after being read from the database.
+#[derive(Debug, Fail)]
+#[fail(display = "could not deserialize {}", name)]
+pub struct DeserializationError {
+    pub name: String,
+}
+
+/// Everything that can go wrong while handling the `/callback` request: a
+/// failed token exchange, an `{"error": ...}` body from Auth0, an invalid
+/// JWT, or a database hiccup. Implements Rocket's `Responder` so handlers
+/// can return `Result<T, CallbackError>` directly instead of collapsing
+/// every failure into a bare `Status`.
+#[derive(Debug, Fail)]
+pub enum CallbackError {
+    #[fail(display = "the \"state\" param did not match our cookie")]
+    StateMismatch,
+    #[fail(display = "missing \"state\" cookie")]
+    MissingStateCookie,
+    #[fail(display = "missing PKCE verifier cookie")]
+    MissingPkceVerifier,
+    #[fail(display = "could not reach Auth0's token endpoint: {}", reason)]
+    TokenRequestFailed { reason: String },
+    #[fail(
+        display = "Auth0 rejected the request: {} ({})",
+        error, error_description
+    )]
+    Auth0Rejected {
+        error: String,
+        error_description: String,
+    },
+    #[fail(display = "invalid JWT: {}", reason)]
+    InvalidJwt { reason: String },
+    #[fail(display = "database error: {}", reason)]
+    Database { reason: String },
+}
+
+impl CallbackError {
+    fn status(&self) -> Status {
+        match self {
+            CallbackError::StateMismatch => Status::Forbidden,
+            CallbackError::MissingStateCookie => Status::BadRequest,
+            CallbackError::MissingPkceVerifier => Status::BadRequest,
+            CallbackError::TokenRequestFailed { .. } => Status::BadGateway,
+            CallbackError::Auth0Rejected { .. } => Status::Unauthorized,
+            CallbackError::InvalidJwt { .. } => Status::Unauthorized,
+            CallbackError::Database { .. } => Status::InternalServerError,
+        }
+    }
+}
+
+impl<'r> Responder<'r> for CallbackError {
+    fn respond_to(self, request: &Request) -> response::Result<'r> {
+        let status = self.status();
+        let wants_json = request
+            .headers()
+            .get_one("Accept")
+            .map(|accept| accept.contains("application/json"))
+            .unwrap_or(false);
+
+        if wants_json {
+            let body = serde_json::json!({ "error": self.to_string() }).to_string();
+            Response::build()
+                .status(status)
+                .header(ContentType::JSON)
+                .sized_body(Cursor::new(body))
+                .ok()
+        } else {
+            let body = html! {
+                head { title { "Error | Auth0 Rocket Example" } }
+                body {
+                    h1 { "Something went wrong" }
+                    p { (self.to_string()) }
+                }
+            }
+            .into_string();
+            Response::build()
+                .status(status)
+                .header(ContentType::HTML)
+                .sized_body(Cursor::new(body))
+                .ok()
+        }
+    }
+}
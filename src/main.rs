@@ -5,8 +5,6 @@
 #[macro_use]
 extern crate failure_derive;
 
-use rocket::{get, routes};
-use serde_derive::{Serialize, Deserialize};
 use bincode::{deserialize, serialize};
 use chrono::Utc;
 use crypto_hash::hex_digest;
@@ -15,6 +13,10 @@ use failure::Error;
 use frank_jwt::{decode, Algorithm};
 use keyz::{make_key, Key};
 use maud::{html, Markup};
+use openssl::bn::BigNum;
+use openssl::pkey::PKey;
+use openssl::rsa::Rsa;
+use openssl::x509::X509;
 use rocket::config::ConfigError;
 use rocket::fairing::AdHoc;
 use rocket::http::uri::Uri;
@@ -22,11 +24,15 @@ use rocket::http::{Cookie, Cookies, Status};
 use rocket::request::{FromRequest, Outcome, Request};
 use rocket::response::Redirect;
 use rocket::State;
+use rocket::{get, routes};
+use serde_derive::{Deserialize, Serialize};
 use serde_json::ser::to_vec;
 use serde_json::Value;
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
 use url::Url;
 
 use errors::*;
@@ -50,7 +56,7 @@ fn main() {
         .attach(AdHoc::on_attach("secrets", |rocket: rocket::Rocket| {
             let conf = rocket.config().clone();
             let settings = AuthSettings::from_env(&conf, "AUTH0_CLIENT_SECRET")
-                .expect("AUTH0_CLIENT_SECRET must be set in your environment");
+                .unwrap_or_else(|e| panic!("missing or invalid setting in Rocket.toml: {}", e));
             {
                 // a call to state() borrows the rocket instance, but we can
                 // introduce a lexical scope to limit our temporary borrow.
@@ -58,52 +64,196 @@ fn main() {
                 populate_certs(db, &settings.auth0_domain)
                     .map_err(|e| panic!("populate_certs: {:?}", e))
                     .unwrap();
+                spawn_session_sweeper(db.clone(), settings.session_sweep_interval_seconds);
             }
             Ok(rocket.manage(settings))
         }))
         .launch();
 }
 
-fn populate_certs(db: &DB, auth0_domain: &str) -> Result<(), Error> {
+/// Deletes every `sessions/*` record whose `expires` timestamp has passed.
+fn sweep_expired_sessions(db: &DB) {
+    let expired_keys: Vec<_> = db
+        .scan_prefix(b"sessions/")
+        .filter_map(|kv| kv.ok())
+        .filter_map(|(key, value)| {
+            let session: Session = deserialize(&value).ok()?;
+            if session.expired() {
+                Some(key)
+            } else {
+                None
+            }
+        })
+        .collect();
+    for key in expired_keys {
+        if let Err(e) = db.del(key) {
+            // A hiccup deleting one key shouldn't take down the sweeper
+            // thread for the rest of the process's life.
+            println!("session sweep: could not delete expired session: {:?}", e);
+        }
+    }
+}
+
+/// Spawns a background thread that periodically sweeps expired sessions out
+/// of sled, so revoked/expired credentials don't linger in `.data` forever.
+fn spawn_session_sweeper(db: DB, interval_seconds: u64) {
+    thread::spawn(move || loop {
+        thread::sleep(Duration::from_secs(interval_seconds));
+        sweep_expired_sessions(&db);
+    });
+}
+
+/// A single signing key as published by Auth0's JWKS endpoint.
+#[derive(Debug, Serialize, Deserialize)]
+struct Jwk {
+    kid: String,
+    kty: String,
+    n: String,
+    e: String,
+    #[serde(default)]
+    x5c: Vec<String>,
+}
+
+/// The document served at `/.well-known/jwks.json`.
+#[derive(Debug, Serialize, Deserialize)]
+struct Jwks {
+    keys: Vec<Jwk>,
+}
+
+/// Fetches the tenant's JWKS document.
+fn fetch_jwks(auth0_domain: &str) -> Result<Jwks, Error> {
     let client = reqwest::Client::new();
-    let cert_endpoint = format!("https://{}/pem", auth0_domain);
-    let pem_cert: String = client
+    let jwks_endpoint = format!("https://{}/.well-known/jwks.json", auth0_domain);
+    let jwks: Jwks = client
         .get(
-            Url::from_str(&cert_endpoint)
+            Url::from_str(&jwks_endpoint)
                 .expect("could not parse auth0_domain")
                 .as_str(),
         )
         .send()?
-        .text()?;
-    // transform cert into X509 struct
-    use openssl::x509::X509;
-    let cert = X509::from_pem(pem_cert.as_bytes()).expect("x509 parse failed");
-    let pk = cert.public_key()?;
-    // extract public keys and cert in pem and der
-    let pem_pk = pk.public_key_to_pem()?;
-    let der_pk = pk.public_key_to_der()?;
-    let der_cert = cert.to_der()?;
-    // save as bytes to database
-    db.set(b"jwt_pub_key_pem".to_vec(), pem_pk).unwrap();
-    db.set(b"jwt_pub_key_der".to_vec(), der_pk).unwrap();
-    db.set(b"jwt_cert_der".to_vec(), der_cert).unwrap();
+        .json()?;
+    Ok(jwks)
+}
+
+/// Turns a single JWKS entry into a PEM-encoded RSA public key, preferring
+/// the `x5c` certificate chain when present and falling back to
+/// reconstructing the key from the `n`/`e` modulus and exponent.
+fn jwk_to_pem(jwk: &Jwk) -> Result<Vec<u8>, Error> {
+    if let Some(cert_b64) = jwk.x5c.get(0) {
+        let der_cert = base64::decode(cert_b64).map_err(|_| AuthError::MalformedJWKS)?;
+        let cert = X509::from_der(&der_cert).map_err(|_| AuthError::MalformedJWKS)?;
+        Ok(cert.public_key()?.public_key_to_pem()?)
+    } else {
+        let n = base64::decode_config(&jwk.n, base64::URL_SAFE_NO_PAD)
+            .map_err(|_| AuthError::MalformedJWKS)?;
+        let e = base64::decode_config(&jwk.e, base64::URL_SAFE_NO_PAD)
+            .map_err(|_| AuthError::MalformedJWKS)?;
+        let rsa = Rsa::from_public_components(
+            BigNum::from_slice(&n).map_err(|_| AuthError::MalformedJWKS)?,
+            BigNum::from_slice(&e).map_err(|_| AuthError::MalformedJWKS)?,
+        )
+        .map_err(|_| AuthError::MalformedJWKS)?;
+        Ok(PKey::from_rsa(rsa)?.public_key_to_pem()?)
+    }
+}
+
+/// Stores each key in a JWKS document under `jwks/{kid}`, PEM-encoded.
+fn store_jwks(db: &DB, jwks: &Jwks) -> Result<(), Error> {
+    for jwk in &jwks.keys {
+        let pem = jwk_to_pem(jwk)?;
+        let jwk_key = make_key!("jwks/", jwk.kid.clone());
+        db.set(jwk_key.0, pem).unwrap();
+    }
     Ok(())
 }
 
+/// Fetches the tenant's JWKS and stores every signing key it publishes.
+/// Auth0 tenants can publish more than one active key at a time (and rotate
+/// them over time), so we keep all of them around rather than a single PEM.
+fn populate_certs(db: &DB, auth0_domain: &str) -> Result<(), Error> {
+    refetch_jwks(db, auth0_domain)
+}
+
+/// Minimum time between JWKS refetches triggered by an unrecognized `kid`.
+/// Without this, a client sending junk JWTs with random `kid` values could
+/// make us hit Auth0's `.well-known/jwks.json` once per request.
+const JWKS_MIN_REFETCH_INTERVAL_SECONDS: i64 = 60;
+
+fn jwks_last_fetched_at(db: &DB) -> Option<i64> {
+    db.get(b"jwks_last_fetched_at")
+        .ok()
+        .flatten()
+        .and_then(|v| deserialize(&v).ok())
+}
+
+/// Fetches the JWKS, stores every key, and records when we did so (so
+/// `lookup_jwk_pem` can rate-limit refetches triggered by unknown `kid`s).
+fn refetch_jwks(db: &DB, auth0_domain: &str) -> Result<(), Error> {
+    let jwks = fetch_jwks(auth0_domain)?;
+    store_jwks(db, &jwks)?;
+    let encoded = serialize(&Utc::now().timestamp()).map_err(|_| SerializationError {
+        name: format!("jwks_last_fetched_at"),
+    })?;
+    db.set(b"jwks_last_fetched_at".to_vec(), encoded)?;
+    Ok(())
+}
+
+/// Reads the unverified `kid` out of a JWT's header segment.
+fn jwt_kid(jwt: &str) -> Result<String, Error> {
+    let malformed = || AuthError::MalformedJWT {
+        repr: jwt.to_string(),
+    };
+    let header_b64 = jwt.split('.').next().ok_or_else(malformed)?;
+    let header_bytes =
+        base64::decode_config(header_b64, base64::URL_SAFE_NO_PAD).map_err(|_| malformed())?;
+    let header: Value = serde_json::from_slice(&header_bytes).map_err(|_| malformed())?;
+    header
+        .get("kid")
+        .and_then(|k| k.as_str())
+        .map(String::from)
+        .ok_or_else(malformed)
+}
+
+/// Looks up the PEM-encoded public key for `kid`. If it isn't known yet
+/// (e.g. Auth0 rotated to a new signing key since we last fetched the JWKS),
+/// refetch the JWKS once before giving up — but only if we haven't already
+/// refetched within `JWKS_MIN_REFETCH_INTERVAL_SECONDS`. Without that check,
+/// a client sending JWTs with bogus `kid`s could force a refetch against
+/// Auth0 on every single request.
+fn lookup_jwk_pem(db: &DB, auth0_domain: &str, kid: &str) -> Result<String, Error> {
+    let jwk_key = make_key!("jwks/", kid.to_string());
+    if let Some(pem) = db.get(&jwk_key.0)? {
+        return String::from_utf8(pem.to_vec()).map_err(|_| AuthError::MalformedJWKS.into());
+    }
+    let unknown = || {
+        Err(AuthError::UnknownSigningKey {
+            kid: kid.to_string(),
+        })?
+    };
+    if let Some(last_fetched_at) = jwks_last_fetched_at(db) {
+        if Utc::now().timestamp() - last_fetched_at < JWKS_MIN_REFETCH_INTERVAL_SECONDS {
+            return unknown();
+        }
+    }
+    refetch_jwks(db, auth0_domain)?;
+    match db.get(&jwk_key.0)? {
+        Some(pem) => String::from_utf8(pem.to_vec()).map_err(|_| AuthError::MalformedJWKS.into()),
+        None => unknown(),
+    }
+}
+
 fn decode_and_validate_jwt(
-    pub_key: Vec<u8>,
+    db: &DB,
     jwt: &str,
     aud: &str,
     auth0_domain: &str,
 ) -> Result<Auth0JWTPayload, Error> {
-    let (_, json) = decode(
-        &jwt.to_string(),
-        &String::from_utf8(pub_key).expect("pk is not valid UTF-8"),
-        Algorithm::RS256,
-    )
-    .map_err(|_| AuthError::MalformedJWT {
-        repr: jwt.to_string(),
-    })?;
+    let kid = jwt_kid(jwt)?;
+    let pem = lookup_jwk_pem(db, auth0_domain, &kid)?;
+    let (_, json) =
+        decode(&jwt.to_string(), &pem, Algorithm::RS256).map_err(|_| AuthError::MalformedJWT {
+            repr: jwt.to_string(),
+        })?;
     let payload = Auth0JWTPayload::from_json(&json)?;
     // We've decoded the jwt payload. Now we validate some fields.
     let now = Utc::now().timestamp();
@@ -119,20 +269,72 @@ fn decode_and_validate_jwt(
     Ok(payload)
 }
 
+/// Checks a JWT `aud` claim against an expected audience. Auth0 access
+/// tokens carry `aud` as either a single string or (when a token is valid
+/// for more than one API) an array of strings, unlike id_tokens, which are
+/// always a single string.
+fn audience_matches(aud: &Value, expected: &str) -> bool {
+    match aud {
+        Value::String(s) => s == expected,
+        Value::Array(values) => values.iter().any(|v| v.as_str() == Some(expected)),
+        _ => false,
+    }
+}
+
+/// Validates an Auth0 *access* token, as presented via `Authorization:
+/// Bearer` by API clients. This is deliberately separate from
+/// `decode_and_validate_jwt`: access tokens are scoped to an API identifier
+/// (`api_audience`) rather than the web client's `client_id`, and by default
+/// carry a `sub` claim instead of `email`/`user_id`.
+fn decode_and_validate_access_token(
+    db: &DB,
+    jwt: &str,
+    api_audience: &str,
+    auth0_domain: &str,
+) -> Result<Auth0AccessTokenClaims, Error> {
+    let kid = jwt_kid(jwt)?;
+    let pem = lookup_jwk_pem(db, auth0_domain, &kid)?;
+    let (_, json) =
+        decode(&jwt.to_string(), &pem, Algorithm::RS256).map_err(|_| AuthError::MalformedJWT {
+            repr: jwt.to_string(),
+        })?;
+    let claims: Auth0AccessTokenClaims =
+        serde_json::from_value(json.clone()).map_err(|_| AuthError::MalformedJWT {
+            repr: format!("{:?}", json),
+        })?;
+    let now = Utc::now().timestamp();
+    if claims.exp < now {
+        return Err(AuthError::Expired)?;
+    };
+    if !audience_matches(&claims.aud, api_audience) {
+        return Err(AuthError::AudienceMismatch)?;
+    };
+    if claims.iss != format!("https://{}/", auth0_domain) {
+        return Err(AuthError::IssuerMismatch)?;
+    };
+    Ok(claims)
+}
+
 fn get_or_create_user(db: &DB, jwt: &Auth0JWTPayload) -> Result<User, Error> {
-    let user_key = make_key!("users/", jwt.user_id.clone());
+    get_or_create_user_by_id(db, &jwt.user_id, &jwt.email)
+}
+
+/// Looks up a `User` by its Auth0 subject id, creating one (with the given
+/// email, which access tokens typically don't carry) the first time we see it.
+fn get_or_create_user_by_id(db: &DB, user_id: &str, email: &str) -> Result<User, Error> {
+    let user_key = make_key!("users/", user_id.to_string());
 
     let user = match db.get(&user_key.0)? {
         None => {
             // user was not found, make a new one
             let user = User {
-                email: jwt.email.clone(),
-                user_id: jwt.user_id.clone(),
+                email: email.to_string(),
+                user_id: user_id.to_string(),
             };
             let encoded_user = serialize(&user).map_err(|_| SerializationError {
                 name: format!("user"),
             })?;
-            db.set(user_key.0, encoded_user).unwrap();
+            db.set(user_key.0, encoded_user)?;
             Ok(user)
         }
         // Some(sled::IVec)
@@ -155,6 +357,7 @@ fn get_routes() -> Vec<rocket::Route> {
         logged_in,
         auth0_redirect,
         auth0_callback,
+        logout,
         home,
         home_redirect,
         static_files
@@ -222,7 +425,16 @@ fn static_files(path: PathBuf) -> Option<rocket::response::NamedFile> {
 fn auth0_redirect(mut cookies: Cookies, settings: State<AuthSettings>) -> Result<Redirect, Status> {
     let state = random_state_string();
     cookies.add(Cookie::new("state", state.clone()));
-    let uri = settings.authorize_endpoint_url(&state);
+
+    let code_verifier = random_code_verifier();
+    let verifier_cookie = Cookie::build("pkce_verifier", code_verifier.clone())
+        .path("/")
+        .secure(true)
+        .http_only(true)
+        .finish();
+    cookies.add(verifier_cookie);
+
+    let uri = settings.authorize_endpoint_url(&state, &code_challenge(&code_verifier));
     println!("{:?}", uri);
     use std::convert::TryFrom;
     let redir = Uri::try_from(uri).expect("invalid uri");
@@ -240,48 +452,60 @@ fn auth0_callback(
     mut cookies: Cookies,
     db: State<DB>,
     settings: State<AuthSettings>,
-) -> Result<Redirect, Status> {
-    if let Some(cookie) = cookies.get("state") {
-        if state != cookie.value() {
-            return Err(rocket::http::Status::Forbidden);
-        }
-    } else {
-        println!("cookie state bad");
-        return Err(rocket::http::Status::BadRequest);
+) -> Result<Redirect, CallbackError> {
+    match cookies.get("state") {
+        Some(cookie) if cookie.value() == state => {}
+        Some(_) => return Err(CallbackError::StateMismatch),
+        None => return Err(CallbackError::MissingStateCookie),
     }
     cookies.remove(Cookie::named("state"));
 
-    let tr = settings.token_request(&code);
+    let code_verifier = match cookies.get("pkce_verifier") {
+        Some(cookie) => cookie.value().to_string(),
+        None => return Err(CallbackError::MissingPkceVerifier),
+    };
+    cookies.remove(Cookie::named("pkce_verifier"));
+
+    let tr = settings.token_request(&code, &code_verifier);
 
-    // TODO: The call to /oauth/token can panic if there are any misconfigurations: The wrong
-    // secret, for instance; also, if the user is unauthorized. We need a nicer way to handle
-    // unauthorized here. Also, we need a nicer way to debug the response. We deserialize directly
-    // into a TokenResponse, but the auth0 api will return this in the event of misconfiguration:
-    //   "{\"error\":\"access_denied\",\"error_description\":\"Unauthorized\"}"
     let token_endpoint = format!("https://{}/oauth/token", settings.auth0_domain);
-    println!("token endpoint time: {:?}", token_endpoint);
     let client = reqwest::Client::new();
-    let resp: TokenResponse = client
+    let body = to_vec(&tr).map_err(|e| CallbackError::TokenRequestFailed {
+        reason: e.to_string(),
+    })?;
+    let token_resp: TokenEndpointResponse = client
         .post(&token_endpoint)
         .header("Content-Type", "application/json")
-        .body(to_vec(&tr).unwrap())
+        .body(body)
         .send()
-        .unwrap()
+        .map_err(|e| CallbackError::TokenRequestFailed {
+            reason: e.to_string(),
+        })?
         .json()
-        .expect("could not deserialize response from /oauth/token");
+        .map_err(|e| CallbackError::TokenRequestFailed {
+            reason: e.to_string(),
+        })?;
+    let resp = match token_resp {
+        TokenEndpointResponse::Success(resp) => resp,
+        TokenEndpointResponse::Error(err) => {
+            return Err(CallbackError::Auth0Rejected {
+                error: err.error,
+                error_description: err.error_description,
+            })
+        }
+    };
 
-    // TODO: Can we unwrap here because we know for certain we've populated the cert in the db?
-    let pub_key: Vec<u8> = db.get(b"jwt_pub_key_pem").unwrap().unwrap().to_vec();
     let payload = decode_and_validate_jwt(
-        pub_key,
+        &db,
         &resp.id_token,
         &settings.client_id,
         &settings.auth0_domain,
     )
-    .map_err(|_| Status::Unauthorized)?;
-    let user = get_or_create_user(&db, &payload).map_err(|e| match e.downcast_ref() {
-        Some(AuthError::MalformedJWT { .. }) => Status::BadRequest,
-        _ => Status::InternalServerError,
+    .map_err(|e| CallbackError::InvalidJwt {
+        reason: e.to_string(),
+    })?;
+    let user = get_or_create_user(&db, &payload).map_err(|e| CallbackError::Database {
+        reason: e.to_string(),
     })?;
 
     let jwt = &resp.id_token.clone();
@@ -290,10 +514,16 @@ fn auth0_callback(
         user_id: user.user_id,
         expires: payload.exp,
         raw_jwt: jwt.as_bytes().to_vec(),
+        refresh_token: resp.refresh_token.map(|t| t.into_bytes()),
     };
-    let encoded_session = serialize(&new_session).map_err(|_| Status::Unauthorized)?;
+    let encoded_session = serialize(&new_session).map_err(|_| CallbackError::Database {
+        reason: "could not serialize session".to_string(),
+    })?;
     let session_key = make_key!("sessions/", hashed_jwt.clone());
-    db.set(session_key.0, encoded_session).unwrap();
+    db.set(session_key.0, encoded_session)
+        .map_err(|e| CallbackError::Database {
+            reason: e.to_string(),
+        })?;
     let cookie = Cookie::build("session", hashed_jwt)
         .path("/")
         .secure(true)
@@ -304,6 +534,32 @@ fn auth0_callback(
     Ok(Redirect::to("/loggedin"))
 }
 
+/// Logs the current session out: removes its DB record, clears the session
+/// cookie, then redirects to Auth0's federated logout endpoint so the
+/// tenant-side session is terminated too, not just ours.
+#[get("/logout")]
+fn logout(mut cookies: Cookies, db: State<DB>, settings: State<AuthSettings>) -> Redirect {
+    if let Some(cookie) = cookies.get("session") {
+        let session_key = make_key!("sessions/", cookie.value().to_string());
+        if let Err(e) = db.del(&session_key.0) {
+            // The user still wants to be logged out and redirected even if
+            // sled hiccups on the delete; the session will be cleaned up
+            // later by the sweep (or just expire) regardless.
+            println!("logout: could not delete session: {:?}", e);
+        }
+    }
+    cookies.remove(Cookie::named("session"));
+
+    let logout_url = format!(
+        "https://{}/v2/logout?client_id={}&returnTo={}",
+        settings.auth0_domain,
+        settings.client_id,
+        Uri::percent_encode(&settings.logout_redirect_uri),
+    );
+    use std::convert::TryFrom;
+    Redirect::to(Uri::try_from(logout_url).expect("invalid uri"))
+}
+
 /// Helper to create a random string 30 chars long.
 pub fn random_state_string() -> String {
     use rand::{distributions::Alphanumeric, thread_rng};
@@ -317,14 +573,36 @@ pub fn random_state_string() -> String {
     random
 }
 
+/// Helper to create a high-entropy PKCE `code_verifier`. Alphanumeric chars
+/// are a subset of the unreserved set PKCE requires, so this is always a
+/// valid verifier; 64 chars comfortably satisfies the 43-128 length bound.
+pub fn random_code_verifier() -> String {
+    use rand::{distributions::Alphanumeric, thread_rng};
+    use std::iter;
+    let mut rng = thread_rng();
+
+    iter::repeat(())
+        .map(|()| rng.sample(Alphanumeric))
+        .take(64)
+        .collect()
+}
+
+/// Computes the PKCE `code_challenge` (S256 method) for a `code_verifier`.
+pub fn code_challenge(code_verifier: &str) -> String {
+    let digest = crypto_hash::digest(HashAlgorithm::SHA256, code_verifier.as_bytes());
+    base64::encode_config(&digest, base64::URL_SAFE_NO_PAD)
+}
+
 /// Send TokenRequest to the Auth0 /oauth/token endpoint.
 #[derive(Serialize, Deserialize)]
 struct TokenRequest {
     grant_type: String,
     client_id: String,
-    client_secret: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    client_secret: Option<String>,
     code: String,
     redirect_uri: String,
+    code_verifier: String,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -333,15 +611,56 @@ struct TokenResponse {
     expires_in: u32,
     id_token: String,
     token_type: String,
+    #[serde(default)]
+    refresh_token: Option<String>,
+}
+
+/// The `/oauth/token` endpoint returns this shape instead of a
+/// `TokenResponse` when the request is rejected, e.g.
+/// `{"error":"access_denied","error_description":"Unauthorized"}`.
+#[derive(Serialize, Deserialize)]
+struct Auth0ErrorResponse {
+    error: String,
+    error_description: String,
+}
+
+/// The two shapes the `/oauth/token` endpoint can return.
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum TokenEndpointResponse {
+    Success(TokenResponse),
+    Error(Auth0ErrorResponse),
 }
 
-/// Configuration state for Auth0, including the client secret, which
-/// must be kept private.
+/// Send a `grant_type=refresh_token` request to the /oauth/token endpoint.
+#[derive(Serialize, Deserialize)]
+struct RefreshTokenRequest {
+    grant_type: String,
+    client_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    client_secret: Option<String>,
+    refresh_token: String,
+}
+
+/// Configuration state for Auth0. `client_secret` is only present for
+/// confidential clients; public clients authenticate with PKCE alone.
 struct AuthSettings {
     client_id: String,
-    client_secret: String,
+    client_secret: Option<String>,
     redirect_uri: String,
     auth0_domain: String,
+    /// Where Auth0 should send the user back to after federated logout.
+    logout_redirect_uri: String,
+    /// How many seconds before a session's actual expiry we should treat it
+    /// as expired and attempt a silent refresh.
+    refresh_skew_seconds: i64,
+    /// How often, in seconds, the background sweeper checks sled for
+    /// expired sessions.
+    session_sweep_interval_seconds: u64,
+    /// The API identifier Auth0 access tokens presented to the Bearer guard
+    /// are expected to be scoped to (the access token's `aud`, which is
+    /// distinct from `client_id`, the id_token's `aud`).
+    api_audience: String,
 }
 
 /// Holds deserialized data from the /oauth/token endpoint. Use the fields
@@ -355,26 +674,35 @@ struct Auth0JWTPayload {
     aud: String,
 }
 
+/// Claims carried by an Auth0 *access* token, as opposed to an id_token:
+/// scoped to an API identifier via `aud` (string or array of strings) and
+/// identifying the subject via `sub` rather than `email`/`user_id`.
+#[derive(Debug, Serialize, Deserialize)]
+struct Auth0AccessTokenClaims {
+    sub: String,
+    exp: i64,
+    iss: String,
+    aud: Value,
+}
+
 impl Auth0JWTPayload {
     /// Creates a Auth0JWTPayload from a subset of fields returned as json
     /// from the /oauth/token endpoint.
     pub fn from_json(json: &Value) -> Result<Auth0JWTPayload, Error> {
         match (
-            json.get("email"),
-            json.get("user_id"),
-            json.get("exp"),
-            json.get("iss"),
-            json.get("aud"),
+            json.get("email").and_then(|v| v.as_str()),
+            json.get("user_id").and_then(|v| v.as_str()),
+            json.get("exp").and_then(|v| v.as_i64()),
+            json.get("iss").and_then(|v| v.as_str()),
+            json.get("aud").and_then(|v| v.as_str()),
         ) {
-            (Some(email), Some(user_id), Some(exp_str), Some(iss), Some(aud)) => {
-                Ok(Auth0JWTPayload {
-                    email: email.as_str().unwrap().to_string(),
-                    user_id: user_id.as_str().unwrap().to_string(),
-                    exp: exp_str.as_i64().unwrap(),
-                    iss: iss.as_str().unwrap().to_string(),
-                    aud: aud.as_str().unwrap().to_string(),
-                })
-            }
+            (Some(email), Some(user_id), Some(exp), Some(iss), Some(aud)) => Ok(Auth0JWTPayload {
+                email: email.to_string(),
+                user_id: user_id.to_string(),
+                exp,
+                iss: iss.to_string(),
+                aud: aud.to_string(),
+            }),
             _ => Err(AuthError::MalformedJWT {
                 repr: format!("{:?}", json.clone()),
             })?,
@@ -391,34 +719,52 @@ impl AuthSettings {
     ) -> Result<AuthSettings, ConfigError> {
         let app_settings = AuthSettings {
             client_id: String::from(conf.get_str("client_id")?),
-            client_secret: std::env::var(client_secret_env_var)
-                .map_err(|_| ConfigError::NotFound)?,
+            client_secret: std::env::var(client_secret_env_var).ok(),
             redirect_uri: String::from(conf.get_str("redirect_uri")?),
             auth0_domain: String::from(conf.get_str("auth0_domain")?),
+            logout_redirect_uri: String::from(conf.get_str("logout_redirect_uri")?),
+            refresh_skew_seconds: conf.get_int("refresh_skew_seconds").unwrap_or(30),
+            session_sweep_interval_seconds: conf
+                .get_int("session_sweep_interval_seconds")
+                .unwrap_or(3600) as u64,
+            api_audience: String::from(conf.get_str("api_audience")?),
         };
         Ok(app_settings)
     }
 
-    /// Given a state param, build a url String that our /auth0 redirect handler can use.
-    pub fn authorize_endpoint_url(&self, state: &str) -> String {
+    /// Given a state param and a PKCE code_challenge, build a url String
+    /// that our /auth0 redirect handler can use.
+    pub fn authorize_endpoint_url(&self, state: &str, code_challenge: &str) -> String {
         format!(
-            "https://{}/authorize?response_type=code&client_id={}&redirect_uri={}&scope=openid%20profile&state={}",
+            "https://{}/authorize?response_type=code&client_id={}&redirect_uri={}&scope=openid%20profile%20offline_access&state={}&code_challenge={}&code_challenge_method=S256",
             self.auth0_domain,
             self.client_id,
             Uri::percent_encode(&self.redirect_uri),
             state,
+            code_challenge,
         )
     }
 
-    /// Builds a TokenRequest from an authorization code and
-    /// Auth0 config values.
-    pub fn token_request(&self, code: &str) -> TokenRequest {
+    /// Builds a TokenRequest from an authorization code, its matching PKCE
+    /// code_verifier, and Auth0 config values.
+    pub fn token_request(&self, code: &str, code_verifier: &str) -> TokenRequest {
         TokenRequest {
             grant_type: String::from("authorization_code"),
             client_id: self.client_id.clone(),
             client_secret: self.client_secret.clone(),
             code: code.to_string(),
             redirect_uri: self.redirect_uri.clone(),
+            code_verifier: code_verifier.to_string(),
+        }
+    }
+
+    /// Builds a RefreshTokenRequest to silently renew an expiring session.
+    pub fn refresh_token_request(&self, refresh_token: &str) -> RefreshTokenRequest {
+        RefreshTokenRequest {
+            grant_type: String::from("refresh_token"),
+            client_id: self.client_id.clone(),
+            client_secret: self.client_secret.clone(),
+            refresh_token: refresh_token.to_string(),
         }
     }
 }
@@ -430,6 +776,7 @@ struct Session {
     user_id: String,
     expires: i64,
     raw_jwt: Vec<u8>,
+    refresh_token: Option<Vec<u8>>,
 }
 
 impl Session {
@@ -438,6 +785,64 @@ impl Session {
         let now = Utc::now().timestamp();
         self.expires <= now
     }
+
+    /// Check if the session is expired, or will expire within `skew_seconds`.
+    pub fn expiring_within(&self, skew_seconds: i64) -> bool {
+        let now = Utc::now().timestamp();
+        self.expires - skew_seconds <= now
+    }
+}
+
+/// Silently renews a session that has expired or is about to, using its
+/// stored refresh token. On success, the old session record is deleted and
+/// a new one is written under a key derived from the new id_token; the
+/// caller is responsible for pointing the session cookie at it.
+fn refresh_session(
+    db: &DB,
+    settings: &AuthSettings,
+    old_session_key: &[u8],
+    session: &Session,
+) -> Result<(Session, String), Error> {
+    let refresh_token = session
+        .refresh_token
+        .clone()
+        .ok_or(AuthError::NoRefreshToken)?;
+    let refresh_token = String::from_utf8(refresh_token).map_err(|_| AuthError::NoRefreshToken)?;
+
+    let rr = settings.refresh_token_request(&refresh_token);
+    let token_endpoint = format!("https://{}/oauth/token", settings.auth0_domain);
+    let client = reqwest::Client::new();
+    let resp: TokenResponse = client
+        .post(&token_endpoint)
+        .header("Content-Type", "application/json")
+        .body(to_vec(&rr)?)
+        .send()?
+        .json()?;
+
+    let payload = decode_and_validate_jwt(
+        db,
+        &resp.id_token,
+        &settings.client_id,
+        &settings.auth0_domain,
+    )?;
+
+    let raw_jwt = resp.id_token.as_bytes().to_vec();
+    let hashed_jwt = hex_digest(HashAlgorithm::SHA256, &raw_jwt);
+    let new_session = Session {
+        user_id: payload.user_id,
+        expires: payload.exp,
+        raw_jwt,
+        // Auth0 may or may not rotate the refresh token; keep the old one if not.
+        refresh_token: Some(resp.refresh_token.unwrap_or(refresh_token).into_bytes()),
+    };
+    let encoded_session = serialize(&new_session).map_err(|_| SerializationError {
+        name: format!("session"),
+    })?;
+    let new_session_key = make_key!("sessions/", hashed_jwt.clone());
+    db.set(new_session_key.0, encoded_session).unwrap();
+    db.del(old_session_key).unwrap();
+
+    Ok((new_session, hashed_jwt))
 }
 
 /// User implements a Rocket request guard that uses a session cookie to
@@ -449,6 +854,36 @@ struct User {
     email: String,
 }
 
+/// Fallback request guard logic for API clients: when there's no `session`
+/// cookie, look for an `Authorization: Bearer <token>` header and validate
+/// it as an Auth0 access token (see `decode_and_validate_access_token`),
+/// resolving the user from the token's `sub` claim.
+fn user_from_bearer_token(request: &Request) -> Outcome<User, ()> {
+    let token = match request.headers().get_one("Authorization") {
+        Some(header) if header.starts_with("Bearer ") => &header["Bearer ".len()..],
+        _ => {
+            println!("no session id");
+            return rocket::Outcome::Forward(());
+        }
+    };
+
+    let db = State::<DB>::from_request(request).unwrap();
+    let settings = State::<AuthSettings>::from_request(request).unwrap();
+    let claims = match decode_and_validate_access_token(
+        &db,
+        token,
+        &settings.api_audience,
+        &settings.auth0_domain,
+    ) {
+        Ok(claims) => claims,
+        Err(_) => return rocket::Outcome::Forward(()),
+    };
+    match get_or_create_user_by_id(&db, &claims.sub, "") {
+        Ok(user) => rocket::Outcome::Success(user),
+        Err(_) => rocket::Outcome::Forward(()),
+    }
+}
+
 impl<'a, 'r> FromRequest<'a, 'r> for User {
     type Error = ();
     fn from_request(request: &'a Request<'r>) -> Outcome<User, ()> {
@@ -457,21 +892,40 @@ impl<'a, 'r> FromRequest<'a, 'r> for User {
             .get("session")
             .and_then(|cookie| cookie.value().parse().ok());
         match session_id {
-            None => {
-                println!("no session id");
-                rocket::Outcome::Forward(())
-            }
+            None => user_from_bearer_token(request),
             Some(session_id) => {
                 println!("session id: {}", session_id);
                 let db = State::<DB>::from_request(request).unwrap();
+                let settings = State::<AuthSettings>::from_request(request).unwrap();
                 let session_key = make_key!("sessions/", session_id);
                 match db.get(&session_key.0) {
                     Ok(Some(sess)) => {
-                        let sess: Session =
-                            deserialize(&sess).expect("could not deserialize session");
-                        if sess.expired() {
-                            return rocket::Outcome::Forward(());
-                        }
+                        // Sessions are stored with bincode, which is positional rather than
+                        // self-describing: a record written under an older `Session` shape
+                        // (e.g. before `refresh_token` was added) won't decode cleanly under
+                        // the current one. Treat that the same as "no session" rather than
+                        // panicking the request thread; the user just has to log in again,
+                        // which naturally re-derives a session in the current format.
+                        let sess: Session = match deserialize(&sess) {
+                            Ok(sess) => sess,
+                            Err(_) => return rocket::Outcome::Forward(()),
+                        };
+                        let sess = if sess.expiring_within(settings.refresh_skew_seconds) {
+                            match refresh_session(&db, &settings, &session_key.0, &sess) {
+                                Ok((new_sess, new_session_id)) => {
+                                    let cookie = Cookie::build("session", new_session_id)
+                                        .path("/")
+                                        .secure(true)
+                                        .http_only(true)
+                                        .finish();
+                                    request.cookies().add(cookie);
+                                    new_sess
+                                }
+                                Err(_) => return rocket::Outcome::Forward(()),
+                            }
+                        } else {
+                            sess
+                        };
                         let user_key = make_key!("users/", sess.user_id);
                         match db.get(&user_key.0) {
                             Ok(Some(user)) => {